@@ -1,7 +1,9 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 use clap::Parser;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::process::Command;
+use std::process::Stdio;
 use eyre::Result;
 
 const INITIAL_WINDOW: &str = "999";
@@ -22,7 +24,9 @@ enum Cli {
     /// Start a new tmux session
     Start(StartCmd),
     /// List all available session definitions
-    List
+    List(ListCmd),
+    /// Capture a running tmux session into a session definition
+    Capture(CaptureCmd)
 }
 
 #[derive(Parser)]
@@ -36,52 +40,295 @@ struct StartCmd {
     /// Definition file to load
     #[arg(short)]
     file: Option<String>,
+
+    /// Kill the session first if it already exists, instead of attaching to it
+    #[arg(short = 'F', long)]
+    force: bool,
+
+    /// Don't attach to the session after creating it
+    #[arg(short = 'd', long)]
+    detached: bool,
+}
+
+#[derive(Parser)]
+struct ListCmd {
+    /// Only show session definitions whose name contains this substring (case-insensitive)
+    query: Option<String>,
+
+    /// Only print bare session names, one per line, suitable for shell completion
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+#[derive(Parser)]
+struct CaptureCmd {
+    /// Name of the running tmux session to capture
+    session_name: String,
+
+    /// Write the definition into the config dir instead of printing it to stdout
+    #[arg(short, long)]
+    save: bool,
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli {
-        Cli::Start(StartCmd { session_name, alias: _alias, file }) => {
+        Cli::Start(StartCmd { session_name, alias: _alias, file, force, detached }) => {
             // Look for a definition in the following order
             // 1. The user supplied a file explicitly (`-f <file>`)
             // 2a. `$XDG_CONFIG_HOME/precession/<session_name>.yaml`
             // 2b. `~/.config/precession/<session_name>.yaml` if `$XDG_CONFIG_HOME` is unset
             // 3. `./.session.yaml`
-            let path = if let Some(file) = file { 
+            let path = if let Some(file) = file {
                 PathBuf::from(file)
             } else if let Some(session_name) = session_name {
-                let config_dir: PathBuf = 
-                    std::env::var("XDG_HOME_CONFIG")
-                    .map(|path| path.into())
-                    .unwrap_or(std::env::home_dir().unwrap().join(".config"));
-
-
-                config_dir.join(format!("precession/{session_name}.yaml"))
+                config_dir().join(format!("precession/{session_name}.yaml"))
             } else {
                 PathBuf::from("./.session.yaml")
             };
 
             let input = std::fs::read_to_string(path)?;
-            let session: Session = serde_yaml::from_str(&input)?;
-            session.render()?;
+            let mut session: Session = serde_yaml::from_str(&input)?;
+
+            // No name in the definition: default to the enclosing git
+            // repository's root directory, both for the session name and
+            // (if unset) the session root.
+            if session.name.is_empty() {
+                let git_root = find_git_root(&std::env::current_dir()?)
+                    .ok_or_else(|| eyre::eyre!("no session name given, and no enclosing git repository to default it from"))?;
+
+                session.name = git_root.file_name()
+                    .ok_or_else(|| eyre::eyre!("git repository root has no directory name"))?
+                    .to_string_lossy()
+                    .to_string();
+
+                session.root.get_or_insert(git_root);
+            }
+
+            session.render(force, detached)?;
+        },
+
+        Cli::List(ListCmd { query, quiet }) => {
+            list_sessions(query.as_deref(), quiet)?;
         },
 
-        _ => { }
+        Cli::Capture(CaptureCmd { session_name, save }) => {
+            let session = capture_session(&session_name)?;
+            let yaml = serde_yaml::to_string(&session)?;
+
+            if save {
+                let dir = config_dir().join("precession");
+                std::fs::create_dir_all(&dir)?;
+                std::fs::write(dir.join(format!("{session_name}.yaml")), yaml)?;
+            } else {
+                print!("{yaml}");
+            }
+        },
     }
 
     Ok(())
 }
 
-#[derive(Deserialize, Debug)]
+/// Attach to `target`, or `switch-client` if we're already inside tmux.
+fn attach_or_switch(target: &str) -> eyre::Result<()> {
+    let cmd = if std::env::var("TMUX").is_ok() { "switch-client" } else { "attach" };
+
+    Command::new("tmux")
+        .args([cmd, "-t", target])
+        .spawn()?
+        .wait()?;
+
+    Ok(())
+}
+
+/// Walk up from `start` looking for a directory containing `.git`, returning
+/// that directory if found.
+fn find_git_root(start: &std::path::Path) -> Option<PathBuf> {
+    let mut dir = start;
+
+    loop {
+        if dir.join(".git").is_dir() {
+            return Some(dir.to_path_buf());
+        }
+
+        dir = dir.parent()?;
+    }
+}
+
+/// Run `cmd` through the shell, optionally in `root`, and wait for it to finish.
+fn run_in_shell(cmd: &str, root: Option<&std::path::Path>) -> eyre::Result<()> {
+    let mut command = Command::new("sh");
+    command.args(["-c", cmd]);
+
+    if let Some(root) = root {
+        command.current_dir(root);
+    }
+
+    command.spawn()?.wait()?;
+
+    Ok(())
+}
+
+/// `$XDG_CONFIG_HOME`, falling back to `~/.config` if unset.
+fn config_dir() -> PathBuf {
+    std::env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| std::env::home_dir().unwrap().join(".config"))
+}
+
+/// Scan the config dir for session definitions, optionally filtered by a
+/// case-insensitive substring match on the session name, and print them.
+fn list_sessions(query: Option<&str>, quiet: bool) -> Result<()> {
+    let dir = config_dir().join("precession");
+
+    let mut sessions: Vec<Session> = Vec::new();
+
+    if dir.is_dir() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+
+            if path.extension().and_then(|ext| ext.to_str()) != Some("yaml") {
+                continue;
+            }
+
+            let Ok(input) = std::fs::read_to_string(&path) else { continue };
+            let Ok(session) = serde_yaml::from_str::<Session>(&input) else { continue };
+
+            sessions.push(session);
+        }
+    }
+
+    if let Some(query) = query {
+        let query = query.to_lowercase();
+        sessions.retain(|session| session.name.to_lowercase().contains(&query));
+    }
+
+    sessions.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if quiet {
+        for session in &sessions {
+            println!("{}", session.name);
+        }
+    } else {
+        println!("{:<20} {:<40} WINDOWS", "NAME", "ROOT");
+        for session in &sessions {
+            let root = session.root.as_ref()
+                .map(|root| root.to_string_lossy().to_string())
+                .unwrap_or_else(|| "-".into());
+
+            println!("{:<20} {:<40} {}", session.name, root, session.windows.len());
+        }
+    }
+
+    Ok(())
+}
+
+/// Introspect the live tmux server and rebuild a `Session` definition from it.
+fn capture_session(name: &str) -> eyre::Result<Session> {
+    let output = Command::new("tmux")
+        .args(["list-windows", "-t", name, "-F", "#{window_index}\t#{window_name}\t#{window_layout}"])
+        .output()?;
+
+    let mut windows = Vec::new();
+    let mut root = None;
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let (window, window_root) = capture_window(name, line)?;
+
+        if root.is_none() {
+            root = window_root;
+        }
+
+        windows.push(window);
+    }
+
+    Ok(Session {
+        name: name.to_string(),
+        root,
+        windows,
+        env: HashMap::new(),
+        attach: default_attach(),
+        on_start: Vec::new(),
+        on_finish: Vec::new(),
+    })
+}
+
+/// Capture a single window (as reported by `list-windows -F ...`), returning
+/// the window definition along with its root, so the caller can default the
+/// session root to the first window's.
+fn capture_window(session: &str, line: &str) -> eyre::Result<(Window, Option<PathBuf>)> {
+    let mut fields = line.splitn(3, '\t');
+    let index = fields.next().unwrap_or_default();
+    let name = fields.next().unwrap_or_default();
+    let layout = fields.next().unwrap_or_default();
+    let target = format!("{session}:{index}");
+
+    let output = Command::new("tmux")
+        .args(["list-panes", "-t", &target, "-F", "#{pane_current_path}\t#{pane_current_command}"])
+        .output()?;
+
+    let mut panes: Vec<(PathBuf, Pane)> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| {
+            let mut fields = line.splitn(2, '\t');
+            let path = PathBuf::from(fields.next().unwrap_or_default());
+            let cmd = fields.next().filter(|cmd| !cmd.is_empty()).map(str::to_string);
+
+            (path, Pane { cmd, split: None, size: None, env: HashMap::new() })
+        })
+        .collect();
+
+    let root = panes.first().map(|(path, _)| path.clone());
+
+    // A single pane maps back onto `Window::cmd`; more than one needs `panes`.
+    let (cmd, panes) = if panes.len() <= 1 {
+        (panes.pop().and_then(|(_, pane)| pane.cmd), None)
+    } else {
+        (None, Some(panes.into_iter().map(|(_, pane)| pane).collect()))
+    };
+
+    let window = Window {
+        name: Some(name.to_string()).filter(|name| !name.is_empty()),
+        layout: layout.to_string().try_into().unwrap_or_default(),
+        root: root.clone(),
+        cmd,
+        panes,
+        env: HashMap::new(),
+        before: Vec::new(),
+    };
+
+    Ok((window, root))
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 struct Session {
+    /// Defaults to the enclosing git repository's root directory name when
+    /// omitted (see the `Start` handling in `main`).
+    #[serde(default)]
     name: String,
     root: Option<PathBuf>,
     #[serde(default)]
-    windows: Vec<Window>
+    windows: Vec<Window>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// Whether to attach to the session after creating it. Defaults to `true`,
+    /// overridden by `--detached` on the command line.
+    #[serde(default = "default_attach")]
+    attach: bool,
+    /// Shell commands run (in `root`) before the session is created.
+    #[serde(default)]
+    on_start: Vec<String>,
+    /// Shell commands run (in `root`) after the session has been finalized.
+    #[serde(default)]
+    on_finish: Vec<String>,
+}
+
+fn default_attach() -> bool {
+    true
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Serialize, Debug, Default)]
 struct Window {
     name: Option<String>,
     #[serde(default)]
@@ -89,23 +336,37 @@ struct Window {
     root: Option<PathBuf>,
     cmd: Option<String>,
     #[serde(default)]
-    panes: Option<Vec<Pane>>
+    panes: Option<Vec<Pane>>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// Commands sent to the window before its `cmd`/`panes` are set up.
+    #[serde(default)]
+    before: Vec<String>,
 }
 
 impl Window {
     fn render(&self) -> eyre::Result<()> {
+        if self.cmd.is_some() && self.panes.is_some() {
+            return Err(eyre::eyre!("a window can't have both `cmd` and `panes` set"));
+        }
+
         self.create()?;
 
-        // TODO: Validation: Either a command _or_ panes, never both!
+        for cmd in &self.before {
+            self.run_cmd(cmd)?;
+        }
+
         if let Some(command) = &self.cmd {
-            self.run_cmd(&command)?;
+            self.run_cmd(command)?;
         }
 
         if let Some(panes) = &self.panes {
             for (i, pane) in panes.iter().enumerate() {
-                if i > 0 { 
+                if i > 0 {
                     pane.create()?;
-                };
+                } else {
+                    pane.export_env()?;
+                }
 
                 pane.render()?;
             }
@@ -131,6 +392,10 @@ impl Window {
             create_cmd.args(["-c", &format!("{}", root.to_string_lossy())]);
         };
 
+        for (key, value) in &self.env {
+            create_cmd.args(["-e", &format!("{key}={value}")]);
+        }
+
         create_cmd.spawn()?.wait()?;
 
         Ok(())
@@ -146,40 +411,50 @@ impl Window {
     }
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(try_from = "String")]
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(try_from = "String", into = "String")]
 enum Layout {
     Tiled,
     EvenHorizontal,
     EvenVertical,
     MainHorizontal,
     MainVertical,
+    /// A raw tmux layout string that doesn't match one of the presets above,
+    /// passed through as-is to `select-layout`.
+    Custom(String),
 }
 
 impl TryFrom<String> for Layout {
     type Error = &'static str;
 
     fn try_from(str: String) -> Result<Self, Self::Error> {
-        match str.as_str() {
-            "tiled" => Ok(Layout::Tiled),
-            "even-vertical" => Ok(Layout::EvenVertical),
-            "even-horizontal" => Ok(Layout::EvenHorizontal),
-            "main-vertical" => Ok(Layout::MainHorizontal),
-            "main-horizontal" => Ok(Layout::MainHorizontal),
-            _ => Err("Not a valid split direction"),
-        }
+        Ok(match str.as_str() {
+            "tiled" => Layout::Tiled,
+            "even-vertical" => Layout::EvenVertical,
+            "even-horizontal" => Layout::EvenHorizontal,
+            "main-vertical" => Layout::MainVertical,
+            "main-horizontal" => Layout::MainHorizontal,
+            _ => Layout::Custom(str),
+        })
+    }
+}
+
+impl From<Layout> for String {
+    fn from(layout: Layout) -> String {
+        layout.to_string()
     }
 }
 
 impl ToString for Layout {
     fn to_string(&self) -> String {
         match self {
-            Layout::Tiled => "tiled",
-            Layout::EvenHorizontal => "even-horizontal",
-            Layout::EvenVertical => "even-vertical",
-            Layout::MainHorizontal => "main-horizontal",
-            Layout::MainVertical => "main-vertical",
-        }.to_string()
+            Layout::Tiled => "tiled".to_string(),
+            Layout::EvenHorizontal => "even-horizontal".to_string(),
+            Layout::EvenVertical => "even-vertical".to_string(),
+            Layout::MainHorizontal => "main-horizontal".to_string(),
+            Layout::MainVertical => "main-vertical".to_string(),
+            Layout::Custom(raw) => raw.clone(),
+        }
     }
 }
 
@@ -189,23 +464,72 @@ impl Default for Layout {
     }
 }
 
-#[derive(Debug, Deserialize, Default)]
-struct Pane(Option<String>);
+/// Which way to split a new pane off from its neighbour.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    Horizontal,
+    Vertical,
+}
+
+impl Direction {
+    fn to_flag(self) -> &'static str {
+        match self {
+            Direction::Horizontal => "-h",
+            Direction::Vertical => "-v",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+struct Pane {
+    cmd: Option<String>,
+    /// Which way to split off this pane from the previous one.
+    split: Option<Direction>,
+    /// Size of the new pane, either a percentage (e.g. `"20%"`) or a number
+    /// of cells, passed straight through to `split-window -l`.
+    size: Option<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+}
 
 impl Pane {
     fn render(&self) -> eyre::Result<()> {
-        if let Some(command) = &self.0 {
+        if let Some(command) = &self.cmd {
             self.run_cmd(command)?;
         }
 
         Ok(())
     }
 
+    /// Export this pane's env vars via `send-keys`. Needed for the window's
+    /// initial pane, which tmux creates for us and so never goes through
+    /// `create`'s `-e` flags.
+    fn export_env(&self) -> eyre::Result<()> {
+        for (key, value) in &self.env {
+            self.run_cmd(&format!("export {key}={value}"))?;
+        }
+
+        Ok(())
+    }
+
     fn create(&self) -> eyre::Result<()> {
-        Command::new("tmux")
-            .arg("split-window")
-            .spawn()?
-            .wait()?;
+        let mut create_cmd = Command::new("tmux");
+        create_cmd.arg("split-window");
+
+        if let Some(split) = &self.split {
+            create_cmd.arg(split.to_flag());
+        }
+
+        if let Some(size) = &self.size {
+            create_cmd.args(["-l", size]);
+        }
+
+        for (key, value) in &self.env {
+            create_cmd.args(["-e", &format!("{key}={value}")]);
+        }
+
+        create_cmd.spawn()?.wait()?;
         Ok(())
     }
 
@@ -220,7 +544,23 @@ impl Pane {
 }
 
 impl Session {
-    fn render(&self) -> eyre::Result<()> {
+    fn render(&self, force: bool, detached: bool) -> eyre::Result<()> {
+        if self.exists()? {
+            if force {
+                self.kill()?;
+            } else if detached {
+                // Already running and we don't want to attach: nothing to do.
+                return Ok(());
+            } else {
+                // Already running: just jump to it instead of re-rendering.
+                return attach_or_switch(&self.name);
+            }
+        }
+
+        for cmd in &self.on_start {
+            run_in_shell(cmd, self.root.as_deref())?;
+        }
+
         self.create()?;
 
         // Create all the windows
@@ -228,7 +568,30 @@ impl Session {
             window.render()?;
         }
 
-        self.finalize()?;
+        self.finalize(detached)?;
+
+        for cmd in &self.on_finish {
+            run_in_shell(cmd, self.root.as_deref())?;
+        }
+        Ok(())
+    }
+
+    fn exists(&self) -> eyre::Result<bool> {
+        let status = Command::new("tmux")
+            .args(["has-session", "-t", &self.name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+
+        Ok(status.success())
+    }
+
+    fn kill(&self) -> eyre::Result<()> {
+        Command::new("tmux")
+            .args(["kill-session", "-t", &self.name])
+            .spawn()?
+            .wait()?;
+
         Ok(())
     }
 
@@ -241,12 +604,16 @@ impl Session {
             create_cmd.args(["-c", &format!("{}", root.to_string_lossy())]);
         }
 
+        for (key, value) in &self.env {
+            create_cmd.args(["-e", &format!("{key}={value}")]);
+        }
+
         create_cmd.spawn()?.wait()?;
 
         Ok(())
     }
 
-    fn finalize(&self) -> eyre::Result<()> {
+    fn finalize(&self, detached: bool) -> eyre::Result<()> {
         // Remove the initial window and relabel the window
         Command::new("tmux")
            .args(["kill-window", "-t", &format!("{}:{INITIAL_WINDOW}", &self.name)])
@@ -259,11 +626,9 @@ impl Session {
             .spawn()?
             .wait()?;
 
-        // Attach to the new sesion (Should this be an option as well?
-        Command::new("tmux")
-            .args(["attach", "-t", &format!("{}:1", &self.name)])
-            .spawn()?
-            .wait()?;
+        if !detached && self.attach {
+            attach_or_switch(&format!("{}:1", &self.name))?;
+        }
 
         Ok(())
     }